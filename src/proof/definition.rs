@@ -5,8 +5,10 @@
 
 //! This module has definition of various proofs.
 
-use self::bitmap::{SparseMerkleBitmap};
+use self::bitmap::SparseMerkleBitmap;
+use crate::failure::prelude::*;
 use crate::hasher::{HashValue, SPARSE_MERKLE_PLACEHOLDER_HASH};
+use crate::Result;
 
 /// A proof that can be used to authenticate an element in a Sparse Merkle Tree given trusted root
 /// hash. For example, `TransactionInfoToAccountProof` can be constructed on top of this structure.
@@ -50,10 +52,231 @@ impl SparseMerkleProof {
     pub fn siblings(&self) -> &[HashValue] {
         &self.siblings
     }
+
+    /// Converts `self` to a `CompressedSparseMerkleProof` by recording, in a bitmap, which
+    /// siblings equal `SPARSE_MERKLE_PLACEHOLDER_HASH` and only keeping the rest. This gives an
+    /// O(k)-sized proof for sparse trees where most siblings are default.
+    pub fn compress(&self) -> CompressedSparseMerkleProof {
+        let bitmap: SparseMerkleBitmap = self
+            .siblings
+            .iter()
+            .map(|sibling| *sibling != *SPARSE_MERKLE_PLACEHOLDER_HASH)
+            .collect();
+        let non_default_siblings = self
+            .siblings
+            .iter()
+            .filter(|sibling| **sibling != *SPARSE_MERKLE_PLACEHOLDER_HASH)
+            .cloned()
+            .collect();
+        CompressedSparseMerkleProof {
+            leaf: self.leaf,
+            bitmap,
+            non_default_siblings,
+        }
+    }
+}
+
+/// A more compact version of `SparseMerkleProof` that does not store default siblings. Instead,
+/// a `SparseMerkleBitmap` records which positions were default so they can be spliced back in on
+/// `decompress()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompressedSparseMerkleProof {
+    /// Same as `SparseMerkleProof::leaf`.
+    leaf: Option<(HashValue, HashValue)>,
+
+    /// Tells which siblings of the uncompressed sibling list are not default, one bit per
+    /// sibling in the same top-to-bottom order.
+    bitmap: SparseMerkleBitmap,
+
+    /// The non-default siblings, in the same top-to-bottom order as the uncompressed sibling
+    /// list.
+    non_default_siblings: Vec<HashValue>,
+}
+
+impl CompressedSparseMerkleProof {
+    /// Constructs a new `CompressedSparseMerkleProof` using leaf, bitmap and non-default
+    /// siblings.
+    pub fn new(
+        leaf: Option<(HashValue, HashValue)>,
+        bitmap: Vec<u8>,
+        non_default_siblings: Vec<HashValue>,
+    ) -> Self {
+        CompressedSparseMerkleProof {
+            leaf,
+            bitmap: SparseMerkleBitmap::new(bitmap),
+            non_default_siblings,
+        }
+    }
+
+    /// Returns the leaf node in this proof.
+    pub fn leaf(&self) -> Option<(HashValue, HashValue)> {
+        self.leaf
+    }
+
+    /// Returns the non-default siblings in this proof.
+    pub fn non_default_siblings(&self) -> &[HashValue] {
+        &self.non_default_siblings
+    }
+
+    /// Reconstructs the full sibling list by walking the bitmap and splicing
+    /// `SPARSE_MERKLE_PLACEHOLDER_HASH` back in wherever a sibling was default, producing an
+    /// equivalent `SparseMerkleProof`.
+    ///
+    /// `self` may come straight from untrusted proof bytes, so every inconsistency between the
+    /// bitmap and `non_default_siblings` (either one claiming more non-default siblings than the
+    /// other actually supplies) is reported as an `Err` rather than panicking.
+    pub fn decompress(&self) -> Result<SparseMerkleProof> {
+        let bitmap = self.bitmap.iter()?;
+        let mut non_default_siblings = self.non_default_siblings.iter();
+        let siblings = bitmap
+            .map(|non_default| {
+                if non_default {
+                    non_default_siblings
+                        .next()
+                        .cloned()
+                        .ok_or_else(|| format_err!("Not enough non-default siblings."))
+                } else {
+                    Ok(*SPARSE_MERKLE_PLACEHOLDER_HASH)
+                }
+            })
+            .collect::<Result<Vec<HashValue>>>()?;
+        ensure!(
+            non_default_siblings.next().is_none(),
+            "More non-default siblings than the bitmap accounts for."
+        );
+        ensure!(
+            siblings.last() != Some(&*SPARSE_MERKLE_PLACEHOLDER_HASH),
+            "The bottom-most sibling marked non-default is the default hash."
+        );
+        Ok(SparseMerkleProof::new(self.leaf, siblings))
+    }
+}
+
+/// Either an uncompressed `SparseMerkleProof` or its space-saving `CompressedSparseMerkleProof`
+/// counterpart, so verification functions can accept whichever form the caller has on hand.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AnySparseMerkleProof {
+    Uncompressed(SparseMerkleProof),
+    Compressed(CompressedSparseMerkleProof),
+}
+
+impl AnySparseMerkleProof {
+    /// Expands `self` into an uncompressed `SparseMerkleProof`, decompressing if necessary. Fails
+    /// if `self` is a `Compressed` proof whose bitmap and non-default siblings are inconsistent
+    /// with each other, which an attacker-supplied proof can freely claim.
+    pub fn into_uncompressed(self) -> Result<SparseMerkleProof> {
+        match self {
+            AnySparseMerkleProof::Uncompressed(proof) => Ok(proof),
+            AnySparseMerkleProof::Compressed(proof) => proof.decompress(),
+        }
+    }
+}
+
+impl From<SparseMerkleProof> for AnySparseMerkleProof {
+    fn from(proof: SparseMerkleProof) -> Self {
+        AnySparseMerkleProof::Uncompressed(proof)
+    }
+}
+
+impl From<&SparseMerkleProof> for AnySparseMerkleProof {
+    fn from(proof: &SparseMerkleProof) -> Self {
+        AnySparseMerkleProof::Uncompressed(proof.clone())
+    }
+}
+
+impl From<CompressedSparseMerkleProof> for AnySparseMerkleProof {
+    fn from(proof: CompressedSparseMerkleProof) -> Self {
+        AnySparseMerkleProof::Compressed(proof)
+    }
+}
+
+impl From<&CompressedSparseMerkleProof> for AnySparseMerkleProof {
+    fn from(proof: &CompressedSparseMerkleProof) -> Self {
+        AnySparseMerkleProof::Compressed(proof.clone())
+    }
+}
+
+/// The per-key proof carried by an `UpdateMerkleProof`: enough to verify both this key's state
+/// immediately before the update and, once the update is folded in, its state immediately after.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SparseMerkleUpdateProof {
+    /// Proof of this key's leaf/non-inclusion state against the root hash in effect immediately
+    /// before the update is applied.
+    old_proof: SparseMerkleProof,
+
+    /// Siblings needed to fold this key's post-update leaf up to the root hash in effect
+    /// immediately after the update is applied. May differ from `old_proof`'s siblings when the
+    /// update inserts or deletes a leaf and so reshapes the tree along this path.
+    new_siblings: Vec<HashValue>,
+}
+
+impl SparseMerkleUpdateProof {
+    /// Constructs a new `SparseMerkleUpdateProof` from the pre-update proof and the post-update
+    /// siblings.
+    pub fn new(old_proof: SparseMerkleProof, new_siblings: Vec<HashValue>) -> Self {
+        SparseMerkleUpdateProof {
+            old_proof,
+            new_siblings,
+        }
+    }
+
+    /// Returns the proof of this key's state before the update.
+    pub fn old_proof(&self) -> &SparseMerkleProof {
+        &self.old_proof
+    }
+
+    /// Returns the siblings needed to fold this key's state after the update up to the root.
+    pub fn new_siblings(&self) -> &[HashValue] {
+        &self.new_siblings
+    }
+}
+
+/// A proof that applying an ordered batch of writes transforms `old_root` into `new_root`,
+/// without revealing the rest of the tree. See `verify_update`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UpdateMerkleProof {
+    updates: Vec<SparseMerkleUpdateProof>,
+}
+
+impl UpdateMerkleProof {
+    /// Constructs a new `UpdateMerkleProof` from the per-key proofs, in the same order as the
+    /// updates being verified.
+    pub fn new(updates: Vec<SparseMerkleUpdateProof>) -> Self {
+        UpdateMerkleProof { updates }
+    }
+
+    /// Returns the per-key proofs, in the same order as the updates being verified.
+    pub fn updates(&self) -> &[SparseMerkleUpdateProof] {
+        &self.updates
+    }
+}
+
+/// A proof that a given set of leaves is exactly the leftmost leaves of a Sparse Merkle Tree as
+/// of a given root hash, used to stream a tree's contents in key order (for example when
+/// restoring a tree from a snapshot).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SparseMerkleRangeProof {
+    /// The siblings on the right of the path from the root to the rightmost supplied leaf. The
+    /// ones near the bottom are at the beginning of the vector.
+    right_siblings: Vec<HashValue>,
+}
+
+impl SparseMerkleRangeProof {
+    /// Constructs a new `SparseMerkleRangeProof` using the given right siblings.
+    pub fn new(right_siblings: Vec<HashValue>) -> Self {
+        SparseMerkleRangeProof { right_siblings }
+    }
+
+    /// Returns the right siblings in this proof.
+    pub fn right_siblings(&self) -> &[HashValue] {
+        &self.right_siblings
+    }
 }
 
 mod bitmap {
-    
+    use crate::failure::prelude::*;
+    use crate::Result;
+
     /// The bitmap indicating which siblings are default in a compressed sparse merkle proof. 1
     /// means non-default and 0 means default.  The MSB of the first byte corresponds to the
     /// sibling at the top of the Sparse Merkle Tree. The rightmost 1-bit of the last byte
@@ -66,7 +289,10 @@ mod bitmap {
             SparseMerkleBitmap(bitmap)
         }
 
-        pub fn iter(&self) -> SparseMerkleBitmapIterator {
+        /// Fails if the last byte of the bitmap is zero, since such a bitmap could not have been
+        /// produced by `SparseMerkleProof::compress` and is only reachable from untrusted,
+        /// hand-built proof bytes.
+        pub fn iter(&self) -> Result<SparseMerkleBitmapIterator> {
             SparseMerkleBitmapIterator::new(&self.0)
         }
     }
@@ -86,24 +312,21 @@ mod bitmap {
     }
 
     impl<'a> SparseMerkleBitmapIterator<'a> {
-        fn new(bitmap: &'a [u8]) -> Self {
+        fn new(bitmap: &'a [u8]) -> Result<Self> {
             match bitmap.last() {
                 Some(last_byte) => {
-                    assert_ne!(
-                        *last_byte, 0,
-                        "The last byte of the bitmap should never be zero."
-                    );
-                    SparseMerkleBitmapIterator {
+                    ensure!(*last_byte != 0, "The last byte of the bitmap is zero.");
+                    Ok(SparseMerkleBitmapIterator {
                         bitmap,
                         index: 0,
                         len: bitmap.len() * 8 - last_byte.trailing_zeros() as usize,
-                    }
+                    })
                 }
-                None => SparseMerkleBitmapIterator {
+                None => Ok(SparseMerkleBitmapIterator {
                     bitmap,
                     index: 0,
                     len: 0,
-                },
+                }),
             }
         }
     }
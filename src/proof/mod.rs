@@ -1,43 +1,39 @@
 // Copyright (c) The Libra Core Contributors
 // SPDX-License-Identifier: Apache-2.0
 
-
 mod definition;
 
 use crate::account::AccountStateBlob;
 use crate::Result;
 
+use crate::failure::prelude::*;
 use crate::hasher::{
-        CryptoHash, CryptoHasher, SparseMerkleInternalHasher,
-        SparseMerkleLeafHasher, SPARSE_MERKLE_PLACEHOLDER_HASH,
-    HashValue,
+    CryptoHash, CryptoHasher, HashValue, SparseMerkleInternalHasher, SparseMerkleLeafHasher,
+    SPARSE_MERKLE_PLACEHOLDER_HASH,
 };
-use crate::failure::prelude::*;
 use std::{collections::VecDeque, marker::PhantomData};
 
 pub use crate::proof::definition::{
-   SparseMerkleProof,
+    AnySparseMerkleProof, CompressedSparseMerkleProof, SparseMerkleProof, SparseMerkleRangeProof,
+    SparseMerkleUpdateProof, UpdateMerkleProof,
 };
 
-
-
 /// If `element_blob` is present, verifies an element whose key is `element_key` and value
 /// is `element_blob` exists in the Sparse Merkle Tree using the provided proof.
 /// Otherwise verifies the proof is a valid non-inclusion proof that shows this key doesn't exist
 /// in the tree.
+///
+/// Accepts either a `SparseMerkleProof` or a `CompressedSparseMerkleProof` (or a reference to
+/// either), since anything convertible to `AnySparseMerkleProof` can be expanded to the siblings
+/// and leaf this function needs.
 pub fn verify_sparse_merkle_element(
     expected_root_hash: HashValue,
     element_key: HashValue,
     element_blob: &Option<AccountStateBlob>,
-    sparse_merkle_proof: &SparseMerkleProof,
+    sparse_merkle_proof: impl Into<AnySparseMerkleProof>,
 ) -> Result<()> {
+    let sparse_merkle_proof = sparse_merkle_proof.into().into_uncompressed()?;
     let siblings = sparse_merkle_proof.siblings();
-    ensure!(
-        siblings.len() <= HashValue::LENGTH_IN_BITS,
-        "Sparse Merkle Tree proof has more than {} ({}) siblings.",
-        HashValue::LENGTH_IN_BITS,
-        siblings.len()
-    );
 
     match (element_blob, sparse_merkle_proof.leaf()) {
         (Some(blob), Some((proof_key, proof_value_hash))) => {
@@ -60,34 +56,134 @@ pub fn verify_sparse_merkle_element(
         }
         (Some(_blob), None) => bail!("Expected inclusion proof. Found non-inclusion proof."),
         (None, Some((proof_key, _))) => {
-            // This is a non-inclusion proof.
-            // The proof intends to show that if a leaf node representing `element_key` is inserted,
-            // it will break a currently existing leaf node represented by `proof_key` into a
-            // branch.
-            // `siblings` should prove the route from that leaf node to the root.
+            // This is a non-inclusion proof because a different leaf node occupies the subtree
+            // where `element_key` would live. That leaf's key must actually differ from
+            // `element_key`, or this would really be an inclusion proof for `element_key`.
+            // `verify_proof_shape` below checks the rest of the shape, but it cannot make this
+            // check itself: `verify_update` legitimately calls it with `proof_key == element_key`
+            // to check a key's prior inclusion state.
             ensure!(
                 element_key != proof_key,
                 "Expected non-inclusion proof, but key exists in proof."
             );
-            ensure!(
-                element_key.common_prefix_bits_len(proof_key) >= siblings.len(),
-                "Key would not have ended up in the subtree where the provided key in proof is \
-                 the only existing key, if it existed. So this is not a valid non-inclusion proof."
-            );
         }
         (None, None) => {
-            // This is a non-inclusion proof.
-            // The proof intends to show that if a leaf node representing `element_key` is inserted,
-            // it will show up at a currently empty position.
-            // `sibling` should prove the route from this empty position to the root.
+            // This is a non-inclusion proof because the subtree where `element_key` would live is
+            // empty. `verify_proof_shape` below checks the proof is well-formed for this case.
         }
     }
 
-    let current_hash = match sparse_merkle_proof.leaf() {
+    verify_proof_shape(
+        expected_root_hash,
+        element_key,
+        sparse_merkle_proof.leaf(),
+        siblings,
+    )
+}
+
+/// Verifies that applying `updates`, in order, transforms `old_root` into `new_root`. For each
+/// update this (1) checks the key's prior leaf/non-inclusion state against the root hash current
+/// at that point in the batch, then (2) folds the post-update leaf back up using that update's
+/// `new_siblings`. The resulting hash becomes the root against which the *next* update's prior
+/// state is checked, so sibling hashes invalidated by one update correctly feed into the
+/// verification of later updates that share part of the same path.
+pub fn verify_update(
+    old_root: HashValue,
+    new_root: HashValue,
+    updates: &[(HashValue, Option<AccountStateBlob>)],
+    proof: &UpdateMerkleProof,
+) -> Result<()> {
+    ensure!(
+        updates.len() == proof.updates().len(),
+        "Number of updates ({}) does not match number of per-key proofs ({}).",
+        updates.len(),
+        proof.updates().len()
+    );
+
+    let mut current_root = old_root;
+    for ((element_key, new_value), update_proof) in updates.iter().zip(proof.updates()) {
+        let old_proof = update_proof.old_proof();
+        verify_proof_shape(
+            current_root,
+            *element_key,
+            old_proof.leaf(),
+            old_proof.siblings(),
+        )?;
+
+        let new_siblings = update_proof.new_siblings();
+        ensure!(
+            new_siblings.len() <= HashValue::LENGTH_IN_BITS,
+            "Sparse Merkle Tree proof has more than {} ({}) siblings.",
+            HashValue::LENGTH_IN_BITS,
+            new_siblings.len()
+        );
+        let new_leaf = new_value.as_ref().map(|blob| (*element_key, blob.hash()));
+        current_root = fold_to_root(new_leaf, *element_key, new_siblings);
+    }
+
+    ensure!(
+        current_root == new_root,
+        "Root hashes do not match. Actual new root hash: {:x}. Expected new root hash: {:x}.",
+        current_root,
+        new_root
+    );
+
+    Ok(())
+}
+
+/// Checks that `leaf`/`siblings`, understood as a proof about `element_key`, are structurally a
+/// valid inclusion or non-inclusion proof for that key and fold up to `expected_root_hash`. This
+/// is the part of `verify_sparse_merkle_element` that doesn't depend on a claimed value, so
+/// `verify_update` can reuse it to check a key's prior state without needing to know that state.
+fn verify_proof_shape(
+    expected_root_hash: HashValue,
+    element_key: HashValue,
+    leaf: Option<(HashValue, HashValue)>,
+    siblings: &[HashValue],
+) -> Result<()> {
+    ensure!(
+        siblings.len() <= HashValue::LENGTH_IN_BITS,
+        "Sparse Merkle Tree proof has more than {} ({}) siblings.",
+        HashValue::LENGTH_IN_BITS,
+        siblings.len()
+    );
+    if let Some((proof_key, _)) = leaf {
+        // If `proof_key` differs from `element_key`, this is a non-inclusion proof: it intends to
+        // show that if a leaf node representing `element_key` were inserted, it would break the
+        // existing `proof_key` leaf into a branch. `siblings` must prove the route from that leaf
+        // node to the root, so `element_key` needs to share enough of a prefix with `proof_key` to
+        // have ended up in that subtree.
+        ensure!(
+            proof_key == element_key
+                || element_key.common_prefix_bits_len(proof_key) >= siblings.len(),
+            "Key would not have ended up in the subtree where the provided key in proof is \
+             the only existing key, if it existed. So this is not a valid non-inclusion proof."
+        );
+    }
+
+    let actual_root_hash = fold_to_root(leaf, element_key, siblings);
+    ensure!(
+        actual_root_hash == expected_root_hash,
+        "Root hashes do not match. Actual root hash: {:x}. Expected root hash: {:x}.",
+        actual_root_hash,
+        expected_root_hash
+    );
+
+    Ok(())
+}
+
+/// Folds a leaf (or `SPARSE_MERKLE_PLACEHOLDER_HASH` if there is none) up through `siblings` to
+/// the hash of the subtree rooted above them, using `element_key`'s bits to pick left/right order.
+fn fold_to_root(
+    leaf: Option<(HashValue, HashValue)>,
+    element_key: HashValue,
+    siblings: &[HashValue],
+) -> HashValue {
+    let current_hash = match leaf {
         Some((key, value_hash)) => SparseMerkleLeafNode::new(key, value_hash).hash(),
         None => *SPARSE_MERKLE_PLACEHOLDER_HASH,
     };
-    let actual_root_hash = siblings
+    siblings
         .iter()
         .rev()
         .zip(
@@ -102,17 +198,174 @@ pub fn verify_sparse_merkle_element(
             } else {
                 SparseMerkleInternalNode::new(hash, *sibling_hash).hash()
             }
-        });
+        })
+}
+
+/// Verifies that `leaves`, sorted ascending by key, are exactly the next leftmost leaves of a
+/// Sparse Merkle Tree with root hash `expected_root_hash` after whatever `left_boundary`
+/// summarizes, using the given range proof. This is the proof shape used to stream a tree's
+/// state in key order, for example when restoring a tree from a snapshot.
+///
+/// `left_boundary` is a depth-ascending frontier of `(depth, key, hash)` triples, exactly as
+/// produced by `restore::SparseMerkleRestore`'s own bookkeeping: each entry is the hash of a
+/// subtree of already-verified leaves strictly less than every key in `leaves`, with `key` any
+/// key it contains (every key sharing its first `depth` bits) so its position can still be
+/// compared against `leaves` without re-walking the leaves it summarizes. Pass an empty slice
+/// when `leaves` starts from the very first key in the tree.
+pub fn verify_sparse_merkle_range(
+    expected_root_hash: HashValue,
+    left_boundary: &[(usize, HashValue, HashValue)],
+    leaves: &[(HashValue, HashValue)],
+    proof: &SparseMerkleRangeProof,
+) -> Result<()> {
+    ensure!(!leaves.is_empty(), "No leaves to verify.");
     ensure!(
-        actual_root_hash == expected_root_hash,
+        leaves.windows(2).all(|pair| pair[0].0 < pair[1].0),
+        "Leaves are not sorted in ascending order of keys, or contain duplicate keys."
+    );
+    if let Some(&(_, boundary_key, _)) = left_boundary.last() {
+        ensure!(
+            boundary_key < leaves[0].0,
+            "Left boundary does not precede the leaves being verified."
+        );
+    }
+
+    let (rightmost_key, rightmost_value_hash) = *leaves.last().expect("leaves is not empty.");
+    let earlier_leaves: Vec<RangeLeaf> = left_boundary
+        .iter()
+        .map(|&(_, key, hash)| RangeLeaf::Boundary { key, hash })
+        .chain(
+            leaves[..leaves.len() - 1]
+                .iter()
+                .map(|&(key, value_hash)| RangeLeaf::Leaf(key, value_hash)),
+        )
+        .collect();
+
+    let mut right_siblings = proof.right_siblings().iter();
+    let mut current_hash = SparseMerkleLeafNode::new(rightmost_key, rightmost_value_hash).hash();
+
+    // Like `fold_to_root`, `rightmost_key`'s subtree is collapsed below the depth of its
+    // shallowest real branch: folding must not wrap `current_hash` in placeholder-paired
+    // `Internal` nodes until a real branch is actually reached, since a collapsed subtree's hash
+    // is just its leaf's hash, at any depth. That branch is the deeper of the one where
+    // `rightmost_key` splits from the last earlier leaf and the one holding the bottom-most right
+    // sibling (the `right_siblings().len()`-th level from the top where its bit is 0), so folding
+    // starts just below it. `expanded` tracks whether a real branch has been folded yet; once it
+    // has, every shallower depth is a real (if sometimes still default) branch and must be folded.
+    let left_depth = earlier_leaves
+        .last()
+        .map_or(0, |leaf| leaf.key().common_prefix_bits_len(rightmost_key) + 1);
+    let right_depth = rightmost_key
+        .iter_bits()
+        .enumerate()
+        .filter(|(_, bit)| !bit)
+        .take(proof.right_siblings().len())
+        .last()
+        .map_or(0, |(depth, _)| depth + 1);
+    let mut expanded = false;
+    // `earlier_leaves[..end]` are the earlier leaves not yet folded in; the ones splitting from
+    // `rightmost_key` deepest come last.
+    let mut end = earlier_leaves.len();
+    for depth in (0..left_depth.max(right_depth)).rev() {
+        let bit = rightmost_key
+            .iter_bits()
+            .nth(depth)
+            .expect("depth is within range.");
+        if bit {
+            // `rightmost_key` is a right child at this depth, so the left sibling is the subtree
+            // formed by whichever earlier leaves share this prefix and split off here (those
+            // sharing more were folded in below). Those leaves are fully known,
+            // so this never needs the proof, but an empty result only means "no branch here yet"
+            // while still collapsed; once expanded, it correctly folds in the default hash.
+            let start = earlier_leaves[..end]
+                .partition_point(|leaf| leaf.key().common_prefix_bits_len(rightmost_key) < depth);
+            let left_leaves = &earlier_leaves[start..end];
+            if left_leaves.is_empty() && !expanded {
+                continue;
+            }
+            expanded = true;
+            end = start;
+            let left_hash = subtree_root_hash(left_leaves, depth + 1);
+            current_hash = SparseMerkleInternalNode::new(left_hash, current_hash).hash();
+        } else {
+            // `rightmost_key` is a left child at this depth. Since it is the rightmost of the
+            // leftmost leaves, nothing known can live to its right, so the sibling, if this is a
+            // real branch, must come from the proof. Running out of siblings only means "no
+            // branch here" while still collapsed; once expanded, every depth needs one.
+            match right_siblings.next() {
+                Some(right_hash) => {
+                    expanded = true;
+                    current_hash = SparseMerkleInternalNode::new(current_hash, *right_hash).hash();
+                }
+                None => {
+                    ensure!(!expanded, "Range proof does not have enough siblings.");
+                }
+            }
+        }
+    }
+
+    ensure!(
+        right_siblings.next().is_none(),
+        "Range proof has siblings left over after reaching the root."
+    );
+    ensure!(
+        current_hash == expected_root_hash,
         "Root hashes do not match. Actual root hash: {:x}. Expected root hash: {:x}.",
-        actual_root_hash,
+        current_hash,
         expected_root_hash
     );
 
     Ok(())
 }
 
+/// One entry of the leaves passed to `subtree_root_hash`: either a leaf whose key and value hash
+/// are still visible, or the hash of a subtree already accounted for by a `left_boundary` entry,
+/// kept only as an opaque hash plus the representative `key` needed to sort and split it.
+#[derive(Clone, Copy)]
+enum RangeLeaf {
+    Leaf(HashValue, HashValue),
+    Boundary { key: HashValue, hash: HashValue },
+}
+
+impl RangeLeaf {
+    fn key(&self) -> HashValue {
+        match *self {
+            RangeLeaf::Leaf(key, _) => key,
+            RangeLeaf::Boundary { key, .. } => key,
+        }
+    }
+}
+
+/// Recursively computes the root hash of the minimal subtree containing `leaves`, given that all
+/// of them already share the first `depth` bits of their keys. A lone `RangeLeaf::Boundary` is
+/// always a valid base case regardless of `depth`, the same way a lone `RangeLeaf::Leaf` is:
+/// since keys are sorted ascending and a boundary entry's underlying leaves are already closed
+/// off from ever sharing more than its own recorded depth of prefix with anything after them,
+/// splitting by bit always separates a boundary entry from its neighbors before `depth` can grow
+/// past that point.
+fn subtree_root_hash(leaves: &[RangeLeaf], depth: usize) -> HashValue {
+    match leaves {
+        [] => *SPARSE_MERKLE_PLACEHOLDER_HASH,
+        [RangeLeaf::Leaf(key, value_hash)] => SparseMerkleLeafNode::new(*key, *value_hash).hash(),
+        [RangeLeaf::Boundary { hash, .. }] => *hash,
+        _ => {
+            let split = leaves.partition_point(|leaf| {
+                !leaf
+                    .key()
+                    .iter_bits()
+                    .nth(depth)
+                    .expect("depth is within range.")
+            });
+            let (left, right) = leaves.split_at(split);
+            SparseMerkleInternalNode::new(
+                subtree_root_hash(left, depth + 1),
+                subtree_root_hash(right, depth + 1),
+            )
+            .hash()
+        }
+    }
+}
+
 pub struct MerkleTreeInternalNode<H> {
     left_child: HashValue,
     right_child: HashValue,
@@ -0,0 +1,356 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module restores a Sparse Merkle Tree from chunks of leaves, each chunk authenticated by a
+//! `SparseMerkleRangeProof` against a known target root hash. This is how a node catches up from a
+//! state snapshot instead of replaying every historical transaction.
+
+use crate::account::AccountStateBlob;
+use crate::failure::prelude::*;
+use crate::hasher::{CryptoHash, HashValue, SPARSE_MERKLE_PLACEHOLDER_HASH};
+use crate::proof::{
+    verify_sparse_merkle_range, SparseMerkleInternalNode, SparseMerkleLeafNode,
+    SparseMerkleRangeProof,
+};
+use crate::Result;
+
+/// A node produced while restoring a Sparse Merkle Tree, keyed by its own hash so a caller can
+/// persist it to whatever storage backs their tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RestoredTreeNode {
+    Leaf {
+        hash: HashValue,
+        key: HashValue,
+        value_hash: HashValue,
+    },
+    Internal {
+        hash: HashValue,
+        left: HashValue,
+        right: HashValue,
+    },
+}
+
+/// A state machine that rebuilds a Sparse Merkle Tree from a stream of `add_chunk` calls, each
+/// carrying the next contiguous run of leftmost leaves plus a `SparseMerkleRangeProof` proving
+/// that run against `expected_root_hash`.
+///
+/// Internally it keeps a "frozen" right-frontier: a stack of `(depth, key, hash, collapsed)`
+/// tuples, strictly increasing in depth from the front (root-ward) to the back (leaf-ward), where
+/// each entry is the hash of a subtree, known to be complete, that contains `key` (any key in the
+/// subtree will do, since every key under it shares its first `depth` bits) and is the left
+/// sibling of whatever eventually merges with it. `collapsed` says whether that subtree is still
+/// a lone, not yet merged leaf, since such a subtree's hash is just the leaf's own hash at any
+/// depth above it, rather than a chain of `Internal` nodes down to its real depth. Every new leaf
+/// closes off part of this frontier: depths deeper than the new leaf's divergence point from the
+/// previous leaf can never receive another leaf (all future leaves are larger), so they are
+/// raised up to the divergence depth — folding in `SPARSE_MERKLE_PLACEHOLDER_HASH` at each
+/// intervening level (on whichever side `key`'s bit at that level does not occupy), unless the
+/// subtree is still collapsed, in which case raising it is a no-op — then folded into the
+/// frontier like a carry in a binary counter.
+pub struct SparseMerkleRestore {
+    expected_root_hash: HashValue,
+    frontier: Vec<(usize, HashValue, HashValue, bool)>,
+    previous_key: Option<HashValue>,
+    nodes: Vec<RestoredTreeNode>,
+}
+
+impl SparseMerkleRestore {
+    /// Creates a new restore state machine targeting `expected_root_hash`.
+    pub fn new(expected_root_hash: HashValue) -> Self {
+        SparseMerkleRestore {
+            expected_root_hash,
+            frontier: vec![],
+            previous_key: None,
+            nodes: vec![],
+        }
+    }
+
+    /// Returns the key of the last leaf accepted so far, so a caller can resume a restore that
+    /// was interrupted by asking for the next chunk after this key.
+    pub fn previous_key_hash(&self) -> Option<HashValue> {
+        self.previous_key
+    }
+
+    /// Ingests the next chunk of leaves, sorted ascending by key, proving with `proof` that
+    /// `chunk` are exactly the next leftmost leaves of the tree rooted at `expected_root_hash`
+    /// following every leaf accepted by previous calls. Only `self.frontier` — not the leaves
+    /// themselves — needs to be kept around to verify this, so a restore's memory stays
+    /// proportional to the tree's depth rather than to how many leaves it has already consumed.
+    /// Rejects a chunk that is empty, out of order, contains a duplicate of an already-seen key,
+    /// or whose range proof does not validate.
+    pub fn add_chunk(
+        &mut self,
+        chunk: Vec<(HashValue, AccountStateBlob)>,
+        proof: SparseMerkleRangeProof,
+    ) -> Result<()> {
+        ensure!(!chunk.is_empty(), "Chunk is empty.");
+        ensure!(
+            chunk.windows(2).all(|w| w[0].0 < w[1].0),
+            "Chunk contains out-of-order or duplicate keys."
+        );
+        if let Some(previous_key) = self.previous_key {
+            ensure!(
+                previous_key < chunk[0].0,
+                "Chunk does not continue from where the previous chunk left off."
+            );
+        }
+
+        let leaves: Vec<(HashValue, HashValue)> = chunk
+            .iter()
+            .map(|(key, blob)| (*key, blob.hash()))
+            .collect();
+        // `verify_sparse_merkle_range` only needs the depth, key and hash of each frontier entry;
+        // whether it is still a collapsed leaf is this module's own bookkeeping.
+        let left_boundary: Vec<(usize, HashValue, HashValue)> = self
+            .frontier
+            .iter()
+            .map(|&(depth, key, hash, _)| (depth, key, hash))
+            .collect();
+        verify_sparse_merkle_range(self.expected_root_hash, &left_boundary, &leaves, &proof)?;
+
+        for (key, value_hash) in leaves {
+            self.add_leaf(key, value_hash);
+        }
+
+        Ok(())
+    }
+
+    /// Collapses the frontier to a single root, asserting it matches `expected_root_hash`, and
+    /// returns every internal and leaf node reconstructed along the way.
+    pub fn finish(mut self) -> Result<Vec<RestoredTreeNode>> {
+        while self.frontier.len() > 1 {
+            let (depth, key, hash, collapsed) = self
+                .frontier
+                .pop()
+                .expect("frontier has more than one entry.");
+            let target_depth = self
+                .frontier
+                .last()
+                .expect("frontier has more than one entry.")
+                .0;
+            let raised = self.raise(key, hash, depth, target_depth, collapsed);
+            self.merge_into_frontier(target_depth, key, raised, collapsed);
+        }
+
+        let root_hash = match self.frontier.pop() {
+            Some((depth, key, hash, collapsed)) => self.raise(key, hash, depth, 0, collapsed),
+            None => *SPARSE_MERKLE_PLACEHOLDER_HASH,
+        };
+        ensure!(
+            root_hash == self.expected_root_hash,
+            "Restored root hash {:x} does not match expected root hash {:x}.",
+            root_hash,
+            self.expected_root_hash
+        );
+
+        Ok(self.nodes)
+    }
+
+    /// Adds a single leaf, closing off and merging whatever part of the frontier can never be
+    /// extended again now that we know the next (larger) key.
+    fn add_leaf(&mut self, key: HashValue, value_hash: HashValue) {
+        let leaf_hash = SparseMerkleLeafNode::new(key, value_hash).hash();
+        self.nodes.push(RestoredTreeNode::Leaf {
+            hash: leaf_hash,
+            key,
+            value_hash,
+        });
+
+        if let Some(previous_key) = self.previous_key {
+            // Bits `0..divergence_depth - 1` are shared by `previous_key` and `key`, which differ
+            // at bit `divergence_depth - 1`; this is the depth at which the previous leaf's
+            // subtree becomes a fully decided left sibling.
+            let divergence_depth = previous_key.common_prefix_bits_len(key) + 1;
+            // Every frontier entry deeper than `divergence_depth` belongs to that subtree: raise
+            // the top entry to the next one's depth and merge, until it reaches that depth.
+            loop {
+                let (depth, entry_key, hash, collapsed) = self
+                    .frontier
+                    .pop()
+                    .expect("frontier holds the previous leaf's subtree.");
+                let target_depth = match self.frontier.last() {
+                    Some(&(next_depth, _, _, _)) if next_depth > divergence_depth => next_depth,
+                    _ => divergence_depth,
+                };
+                let closed = self.raise(entry_key, hash, depth, target_depth, collapsed);
+                self.merge_into_frontier(target_depth, entry_key, closed, collapsed);
+                if target_depth == divergence_depth {
+                    break;
+                }
+            }
+        }
+
+        self.frontier
+            .push((HashValue::LENGTH_IN_BITS, key, leaf_hash, true));
+        self.previous_key = Some(key);
+    }
+
+    /// Raises `hash`, currently the root of a subtree at `depth` containing `key`, up to
+    /// `target_depth` by repeatedly pairing it with `SPARSE_MERKLE_PLACEHOLDER_HASH`, recording
+    /// every internal node created along the way. At each level, `key`'s bit decides which side
+    /// `hash` sits on: every key in the subtree shares `key`'s first `depth` bits, and since
+    /// `depth > target_depth` throughout, that includes every bit this loop consults.
+    ///
+    /// If `collapsed` is true, `hash` is still a lone leaf's own hash: since a collapsed
+    /// subtree's hash is the same at any depth above it, raising it is a no-op rather than a
+    /// chain of placeholder-paired `Internal` nodes, mirroring `tree::build_subtree`'s single-leaf
+    /// base case.
+    fn raise(
+        &mut self,
+        key: HashValue,
+        mut hash: HashValue,
+        mut depth: usize,
+        target_depth: usize,
+        collapsed: bool,
+    ) -> HashValue {
+        if collapsed {
+            return hash;
+        }
+        while depth > target_depth {
+            let bit = key
+                .iter_bits()
+                .nth(depth - 1)
+                .expect("depth is within range.");
+            let (left, right) = if bit {
+                (*SPARSE_MERKLE_PLACEHOLDER_HASH, hash)
+            } else {
+                (hash, *SPARSE_MERKLE_PLACEHOLDER_HASH)
+            };
+            let parent_hash = SparseMerkleInternalNode::new(left, right).hash();
+            self.nodes.push(RestoredTreeNode::Internal {
+                hash: parent_hash,
+                left,
+                right,
+            });
+            hash = parent_hash;
+            depth -= 1;
+        }
+        hash
+    }
+
+    /// Merges `hash`, the root of a subtree at `depth` containing `key`, into the frontier. If
+    /// the current frontier top is already at `depth`, it is this subtree's left sibling, so they
+    /// are merged (and the merge is repeated one level up, like a carry in a binary counter);
+    /// otherwise `hash` becomes a new frontier entry awaiting its sibling. A merged entry keeps
+    /// the sibling's key, which works equally well as `key` since both share the merged subtree's
+    /// (shorter) prefix.
+    ///
+    /// `collapsed` is `hash`'s collapsed state coming in; since merging with a real sibling always
+    /// produces a genuine (non-collapsed) two-leaf-or-more subtree, every merge this performs
+    /// turns it permanently false. If no merge happens, the resulting frontier entry keeps
+    /// whatever `collapsed` was passed in.
+    fn merge_into_frontier(
+        &mut self,
+        mut depth: usize,
+        mut key: HashValue,
+        mut hash: HashValue,
+        mut collapsed: bool,
+    ) {
+        while let Some(&(sibling_depth, _, _, _)) = self.frontier.last() {
+            if sibling_depth != depth {
+                break;
+            }
+            let (_, sibling_key, sibling_hash, _) =
+                self.frontier.pop().expect("frontier top was just peeked.");
+            let parent_hash = SparseMerkleInternalNode::new(sibling_hash, hash).hash();
+            self.nodes.push(RestoredTreeNode::Internal {
+                hash: parent_hash,
+                left: sibling_hash,
+                right: hash,
+            });
+            key = sibling_key;
+            hash = parent_hash;
+            depth -= 1;
+            collapsed = false;
+        }
+        self.frontier.push((depth, key, hash, collapsed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proof::SparseMerkleLeafNode;
+    use crate::tree::SparseMerkleTree;
+
+    fn blob(byte: u8) -> AccountStateBlob {
+        AccountStateBlob::from(vec![byte])
+    }
+
+    /// Restores a two-leaf tree whose keys differ only in their very last bit, across two
+    /// single-leaf chunks, exercising both the frontier's placeholder-raising (`raise`) and its
+    /// carry-style merging (`merge_into_frontier`) along the way.
+    #[test]
+    fn restore_round_trips_two_chunks_differing_only_in_the_last_bit() {
+        let key_a = HashValue::new([0u8; HashValue::LENGTH]);
+        let mut key_b_bytes = [0u8; HashValue::LENGTH];
+        key_b_bytes[HashValue::LENGTH - 1] = 1;
+        let key_b = HashValue::new(key_b_bytes);
+
+        let tree = SparseMerkleTree::new(
+            vec![(key_a, blob(1)), (key_b, blob(2))]
+                .into_iter()
+                .collect(),
+        );
+        let leaf_b_hash = SparseMerkleLeafNode::new(key_b, blob(2).hash()).hash();
+
+        // Every depth is a "left, placeholder-right" step for `key_a` except the very last,
+        // where `key_b`'s leaf is the real sibling.
+        let mut first_chunk_siblings =
+            vec![*SPARSE_MERKLE_PLACEHOLDER_HASH; HashValue::LENGTH_IN_BITS];
+        first_chunk_siblings[0] = leaf_b_hash;
+
+        // `key_b` is the right child at the last bit, where its left sibling is `key_a`'s leaf
+        // from the first chunk; every shallower depth is a left-child step whose right sibling
+        // is a placeholder.
+        let second_chunk_siblings =
+            vec![*SPARSE_MERKLE_PLACEHOLDER_HASH; HashValue::LENGTH_IN_BITS - 1];
+
+        let mut restore = SparseMerkleRestore::new(tree.root_hash());
+        restore
+            .add_chunk(
+                vec![(key_a, blob(1))],
+                SparseMerkleRangeProof::new(first_chunk_siblings),
+            )
+            .unwrap();
+        restore
+            .add_chunk(
+                vec![(key_b, blob(2))],
+                SparseMerkleRangeProof::new(second_chunk_siblings),
+            )
+            .unwrap();
+
+        restore.finish().unwrap();
+    }
+
+    /// Restores a tree with several leaves that do not all share a deep key prefix, across
+    /// multiple multi-leaf chunks, using proofs produced by `SparseMerkleTree::range_proof`
+    /// instead of hand-built sibling lists. This is the ordinary case `restore_round_trips_
+    /// two_chunks_differing_only_in_the_last_bit` does not exercise, since that test's two keys
+    /// are the one degenerate shape where always-pad-to-256-bits and the crate's real collapsed
+    /// folding happen to agree.
+    #[test]
+    fn restore_round_trips_a_multi_leaf_non_adjacent_key_tree() {
+        let leaves = vec![
+            (key(1), blob(1)),
+            (key(2), blob(2)),
+            (key(3), blob(3)),
+            (key(4), blob(4)),
+        ];
+        let tree = SparseMerkleTree::new(leaves.clone().into_iter().collect());
+
+        let mut restore = SparseMerkleRestore::new(tree.root_hash());
+        for chunk in leaves.chunks(2) {
+            let rightmost_key = chunk.last().expect("chunk is not empty.").0;
+            let proof = tree.range_proof(rightmost_key);
+            restore.add_chunk(chunk.to_vec(), proof).unwrap();
+        }
+
+        let nodes = restore.finish().unwrap();
+        assert!(!nodes.is_empty());
+    }
+
+    fn key(byte: u8) -> HashValue {
+        HashValue::new([byte; HashValue::LENGTH])
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright (c) The Libra Core Contributors
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module provides an in-memory Sparse Merkle Tree that can produce the proofs this crate
+//! otherwise only knows how to verify.
+
+use crate::account::AccountStateBlob;
+use crate::hasher::{CryptoHash, HashValue, SPARSE_MERKLE_PLACEHOLDER_HASH};
+use crate::proof::{
+    SparseMerkleInternalNode, SparseMerkleLeafNode, SparseMerkleProof, SparseMerkleRangeProof,
+};
+use std::{collections::BTreeMap, rc::Rc};
+
+/// An immutable Sparse Merkle Tree held fully in memory. `update()` returns a new tree that
+/// shares every subtree unaffected by the write with `self`, so applying a batch of writes only
+/// allocates new nodes along the O(log n) paths that actually changed.
+#[derive(Clone)]
+pub struct SparseMerkleTree {
+    root: Rc<Node>,
+}
+
+impl SparseMerkleTree {
+    /// Builds a tree from a full set of populated leaves, for example the genesis state.
+    pub fn new(leaves: BTreeMap<HashValue, AccountStateBlob>) -> Self {
+        let leaves: Vec<(HashValue, AccountStateBlob)> = leaves.into_iter().collect();
+        SparseMerkleTree {
+            root: Rc::new(build_subtree(&leaves, 0)),
+        }
+    }
+
+    /// Returns the root hash of this tree.
+    pub fn root_hash(&self) -> HashValue {
+        self.root.hash()
+    }
+
+    /// Returns a new tree with `writes` applied, where `None` deletes the key. `writes` is
+    /// expected to contain at most one entry per key.
+    pub fn update(
+        &self,
+        mut writes: Vec<(HashValue, Option<AccountStateBlob>)>,
+    ) -> SparseMerkleTree {
+        writes.sort_by_key(|(key, _)| *key);
+        SparseMerkleTree {
+            root: apply_writes(&self.root, &writes, 0),
+        }
+    }
+
+    /// Returns the value at `key`, if any, together with a `SparseMerkleProof` of exactly the
+    /// shape `verify_sparse_merkle_element` expects: an inclusion proof if `key` has a value, or
+    /// a non-inclusion proof against the single leaf (or empty position) that occupies the
+    /// subtree where `key` would otherwise live.
+    pub fn get_with_proof(&self, key: HashValue) -> (Option<AccountStateBlob>, SparseMerkleProof) {
+        let mut node = self.root.as_ref();
+        let mut siblings = vec![];
+        let mut depth = 0;
+        loop {
+            match node {
+                Node::Empty => {
+                    return (
+                        None,
+                        SparseMerkleProof::new(None, drop_default_tail(siblings)),
+                    );
+                }
+                Node::Leaf {
+                    key: leaf_key,
+                    value,
+                    ..
+                } => {
+                    let leaf = Some((*leaf_key, value.hash()));
+                    let found = if *leaf_key == key {
+                        Some(value.clone())
+                    } else {
+                        None
+                    };
+                    return (
+                        found,
+                        SparseMerkleProof::new(leaf, drop_default_tail(siblings)),
+                    );
+                }
+                Node::Internal { left, right, .. } => {
+                    let bit = key.iter_bits().nth(depth).expect("depth is within range.");
+                    let (next, sibling) = if bit { (right, left) } else { (left, right) };
+                    siblings.push(sibling.hash());
+                    node = next.as_ref();
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    /// Returns a `SparseMerkleRangeProof` proving that every leaf with a key less than or equal
+    /// to `rightmost_key` is exactly the set of leaves in this tree up to and including it,
+    /// suitable for `verify_sparse_merkle_range` together with those leaves. `rightmost_key` need
+    /// not itself be present in the tree.
+    pub fn range_proof(&self, rightmost_key: HashValue) -> SparseMerkleRangeProof {
+        let mut node = self.root.as_ref();
+        let mut right_siblings = vec![];
+        let mut depth = 0;
+        while let Node::Internal { left, right, .. } = node {
+            let bit = rightmost_key
+                .iter_bits()
+                .nth(depth)
+                .expect("depth is within range.");
+            if bit {
+                node = right.as_ref();
+            } else {
+                right_siblings.push(right.hash());
+                node = left.as_ref();
+            }
+            depth += 1;
+        }
+        right_siblings.reverse();
+        SparseMerkleRangeProof::new(right_siblings)
+    }
+}
+
+/// Removes the trailing (bottom-most) default siblings, since `SparseMerkleProof` requires the
+/// bottom sibling to never be default.
+fn drop_default_tail(mut siblings: Vec<HashValue>) -> Vec<HashValue> {
+    while siblings.last() == Some(&*SPARSE_MERKLE_PLACEHOLDER_HASH) {
+        siblings.pop();
+    }
+    siblings
+}
+
+enum Node {
+    Empty,
+    Leaf {
+        key: HashValue,
+        value: AccountStateBlob,
+        hash: HashValue,
+    },
+    Internal {
+        left: Rc<Node>,
+        right: Rc<Node>,
+        hash: HashValue,
+    },
+}
+
+impl Node {
+    fn new_leaf(key: HashValue, value: AccountStateBlob) -> Self {
+        let hash = SparseMerkleLeafNode::new(key, value.hash()).hash();
+        Node::Leaf { key, value, hash }
+    }
+
+    fn new_internal(left: Rc<Node>, right: Rc<Node>) -> Self {
+        let hash = SparseMerkleInternalNode::new(left.hash(), right.hash()).hash();
+        Node::Internal { left, right, hash }
+    }
+
+    fn hash(&self) -> HashValue {
+        match self {
+            Node::Empty => *SPARSE_MERKLE_PLACEHOLDER_HASH,
+            Node::Leaf { hash, .. } => *hash,
+            Node::Internal { hash, .. } => *hash,
+        }
+    }
+}
+
+/// Builds the subtree containing exactly `leaves`, all of which are known to already share the
+/// first `depth` bits of their keys. `leaves` must be sorted ascending by key.
+fn build_subtree(leaves: &[(HashValue, AccountStateBlob)], depth: usize) -> Node {
+    match leaves {
+        [] => Node::Empty,
+        [(key, value)] => Node::new_leaf(*key, value.clone()),
+        _ => {
+            let split = leaves.partition_point(|(key, _)| {
+                !key.iter_bits().nth(depth).expect("depth is within range.")
+            });
+            let (left_leaves, right_leaves) = leaves.split_at(split);
+            let left = Rc::new(build_subtree(left_leaves, depth + 1));
+            let right = Rc::new(build_subtree(right_leaves, depth + 1));
+            Node::new_internal(left, right)
+        }
+    }
+}
+
+/// Applies `writes`, all of which are known to belong under `node` at `depth`, returning the
+/// resulting subtree. Branches untouched by any write are shared with `node` via `Rc::clone`
+/// rather than rebuilt.
+fn apply_writes(
+    node: &Rc<Node>,
+    writes: &[(HashValue, Option<AccountStateBlob>)],
+    depth: usize,
+) -> Rc<Node> {
+    if writes.is_empty() {
+        return Rc::clone(node);
+    }
+
+    match node.as_ref() {
+        Node::Empty => Rc::new(build_subtree(&writes_to_leaves(writes, None), depth)),
+        Node::Leaf { key, value, .. } => Rc::new(build_subtree(
+            &writes_to_leaves(writes, Some((*key, value))),
+            depth,
+        )),
+        Node::Internal { left, right, .. } => {
+            let split = writes.partition_point(|(key, _)| {
+                !key.iter_bits().nth(depth).expect("depth is within range.")
+            });
+            let (left_writes, right_writes) = writes.split_at(split);
+            let new_left = apply_writes(left, left_writes, depth + 1);
+            let new_right = apply_writes(right, right_writes, depth + 1);
+            Rc::new(Node::new_internal(new_left, new_right))
+        }
+    }
+}
+
+/// Combines `writes` with the leaf already occupying this position (if any) into a sorted list
+/// of populated leaves, with `writes` taking precedence over `existing` for a shared key.
+fn writes_to_leaves(
+    writes: &[(HashValue, Option<AccountStateBlob>)],
+    existing: Option<(HashValue, &AccountStateBlob)>,
+) -> Vec<(HashValue, AccountStateBlob)> {
+    let mut leaves: Vec<(HashValue, AccountStateBlob)> = writes
+        .iter()
+        .filter_map(|(key, value)| value.clone().map(|value| (*key, value)))
+        .collect();
+    if let Some((existing_key, existing_value)) = existing {
+        if !writes.iter().any(|(key, _)| *key == existing_key) {
+            leaves.push((existing_key, existing_value.clone()));
+        }
+    }
+    leaves.sort_by_key(|(key, _)| *key);
+    leaves
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proof::{
+        verify_sparse_merkle_element, verify_update, SparseMerkleUpdateProof, UpdateMerkleProof,
+    };
+
+    fn key(byte: u8) -> HashValue {
+        HashValue::new([byte; HashValue::LENGTH])
+    }
+
+    fn blob(byte: u8) -> AccountStateBlob {
+        AccountStateBlob::from(vec![byte])
+    }
+
+    #[test]
+    fn get_with_proof_round_trips_through_verify_sparse_merkle_element() {
+        let leaves: BTreeMap<_, _> = vec![(key(1), blob(1)), (key(2), blob(2)), (key(3), blob(3))]
+            .into_iter()
+            .collect();
+        let tree = SparseMerkleTree::new(leaves);
+
+        for k in 1..=3u8 {
+            let (found, proof) = tree.get_with_proof(key(k));
+            assert_eq!(found, Some(blob(k)));
+            verify_sparse_merkle_element(tree.root_hash(), key(k), &found, proof.clone()).unwrap();
+            verify_sparse_merkle_element(tree.root_hash(), key(k), &found, proof.compress())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn get_with_proof_round_trips_non_inclusion() {
+        let leaves: BTreeMap<_, _> = vec![(key(1), blob(1)), (key(3), blob(3))]
+            .into_iter()
+            .collect();
+        let tree = SparseMerkleTree::new(leaves);
+
+        let (found, proof) = tree.get_with_proof(key(2));
+        assert_eq!(found, None);
+        verify_sparse_merkle_element(tree.root_hash(), key(2), &None, proof).unwrap();
+    }
+
+    #[test]
+    fn get_with_proof_rejects_a_false_non_inclusion_claim() {
+        let tree = SparseMerkleTree::new(vec![(key(1), blob(1))].into_iter().collect());
+
+        let (_, proof) = tree.get_with_proof(key(1));
+        assert!(verify_sparse_merkle_element(tree.root_hash(), key(1), &None, proof).is_err());
+    }
+
+    #[test]
+    fn verify_update_round_trips_an_insertion() {
+        let old_tree = SparseMerkleTree::new(vec![(key(1), blob(1))].into_iter().collect());
+        let (_, old_proof) = old_tree.get_with_proof(key(2));
+
+        let new_tree = old_tree.update(vec![(key(2), Some(blob(2)))]);
+        let (_, new_proof) = new_tree.get_with_proof(key(2));
+
+        let update_proof = UpdateMerkleProof::new(vec![SparseMerkleUpdateProof::new(
+            old_proof,
+            new_proof.siblings().to_vec(),
+        )]);
+        verify_update(
+            old_tree.root_hash(),
+            new_tree.root_hash(),
+            &[(key(2), Some(blob(2)))],
+            &update_proof,
+        )
+        .unwrap();
+    }
+
+    /// Exercises `verify_update`'s sequential threading of `current_root` across a batch: the two
+    /// keys here differ only in their last bit, so the second update's old-state proof is only
+    /// valid against the root produced by the first update, not against `old_root`.
+    #[test]
+    fn verify_update_threads_current_root_across_overlapping_updates() {
+        let key_a = key(1);
+        let mut key_b_bytes = [1u8; HashValue::LENGTH];
+        key_b_bytes[HashValue::LENGTH - 1] ^= 1;
+        let key_b = HashValue::new(key_b_bytes);
+
+        let old_tree = SparseMerkleTree::new(
+            vec![(key_a, blob(1)), (key_b, blob(2))]
+                .into_iter()
+                .collect(),
+        );
+        let updates = vec![(key_a, Some(blob(9))), (key_b, None)];
+
+        let after_first_update = old_tree.update(vec![updates[0].clone()]);
+        let new_tree = after_first_update.update(vec![updates[1].clone()]);
+
+        let (_, old_proof_a) = old_tree.get_with_proof(key_a);
+        let (_, new_proof_a) = after_first_update.get_with_proof(key_a);
+        let (_, old_proof_b) = after_first_update.get_with_proof(key_b);
+        let (_, new_proof_b) = new_tree.get_with_proof(key_b);
+
+        let update_proof = UpdateMerkleProof::new(vec![
+            SparseMerkleUpdateProof::new(old_proof_a, new_proof_a.siblings().to_vec()),
+            SparseMerkleUpdateProof::new(old_proof_b, new_proof_b.siblings().to_vec()),
+        ]);
+        verify_update(
+            old_tree.root_hash(),
+            new_tree.root_hash(),
+            &updates,
+            &update_proof,
+        )
+        .unwrap();
+    }
+}